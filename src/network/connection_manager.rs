@@ -3,16 +3,19 @@ use crate::{
     Request, Response, Result,
 };
 use bytes::Bytes;
+use futures::future::BoxFuture;
 use futures::FutureExt;
 use futures::{
     stream::{Fuse, FuturesUnordered},
     StreamExt,
 };
+use rand::Rng;
 use std::{
     collections::{hash_map::Entry, HashMap},
     convert::Infallible,
     net::SocketAddr,
     sync::Arc,
+    time::Duration,
 };
 use tower::util::BoxCloneService;
 use tracing::{error, info};
@@ -25,18 +28,204 @@ pub enum ConnectionManagerRequest {
 struct ConnectingOutput {
     connecting_result: Result<NewConnection>,
     maybe_oneshot: Option<tokio::sync::oneshot::Sender<Result<PeerId>>>,
+    // The address we dialed to produce this connection, if this was an outbound connection
+    // attempt. Used to drive reconnection bookkeeping for persistent peers.
+    target: Option<SocketAddr>,
+}
+
+/// Parameters controlling how aggressively we retry connecting to a persistent peer.
+#[derive(Debug, Clone, Copy)]
+struct ReconnectionPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    factor: f64,
+    // +/- this fraction of the computed delay is added as jitter to avoid thundering-herd
+    // reconnection when many peers drop at once.
+    jitter: f64,
+}
+
+impl Default for ReconnectionPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            factor: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectionPolicy {
+    /// Computes the delay to use for the *next* retry after the one described by `delay`.
+    fn backoff(&self, delay: Duration) -> Duration {
+        delay.mul_f64(self.factor).min(self.max_delay)
+    }
+
+    /// Applies random jitter of +/- `self.jitter` to `delay`.
+    fn jittered(&self, delay: Duration) -> Duration {
+        let jitter = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        delay.mul_f64((1.0 + jitter).max(0.0))
+    }
+}
+
+/// Per-target bookkeeping for a persistent peer we want to keep connected to.
+#[derive(Debug)]
+struct PersistentPeerState {
+    // Whether we currently believe we're connected to this peer.
+    connected: bool,
+    // The delay to use the *next* time we schedule a reconnect to this peer.
+    next_delay: Duration,
+}
+
+impl PersistentPeerState {
+    fn new(initial_delay: Duration) -> Self {
+        Self {
+            connected: false,
+            next_delay: initial_delay,
+        }
+    }
+}
+
+/// Abstracts over how anemo spawns background tasks, so that embedders can run it on something
+/// other than the default multi-threaded tokio runtime, e.g. a current-thread runtime, a custom
+/// thread pool, or a deterministic executor that drives spawned tasks synchronously in tests.
+///
+/// The returned [`AbortOnDropHandle`] is runtime-agnostic: it's built from a pair of closures
+/// rather than a tokio primitive, so an `Executor` impl never needs to call `tokio::spawn` itself
+/// just to produce one.
+pub trait Executor: std::fmt::Debug + Send + Sync {
+    /// Spawns `fut` to run in the background, returning a handle that cancels the task when
+    /// dropped (unless [`detach`](AbortOnDropHandle::detach) is called first).
+    fn spawn(&self, fut: BoxFuture<'static, ()>) -> AbortOnDropHandle;
+}
+
+/// The default [`Executor`], backed by `tokio::spawn`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioExecutor;
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, fut: BoxFuture<'static, ()>) -> AbortOnDropHandle {
+        let abort_handle = tokio::spawn(fut).abort_handle();
+        let is_finished_handle = abort_handle.clone();
+        AbortOnDropHandle::new(
+            move || is_finished_handle.is_finished(),
+            move || abort_handle.abort(),
+        )
+    }
+}
+
+/// A handle to a task spawned through an [`Executor`]. Cancels the task when dropped, unless
+/// [`detach`](Self::detach) is called first.
+///
+/// Built from a pair of closures rather than a concrete runtime type, so that `Executor` impls
+/// backed by something other than tokio (a custom thread pool, a deterministic executor used in
+/// tests) can construct one without needing a tokio task handle at all.
+#[must_use]
+pub struct AbortOnDropHandle {
+    is_finished: Box<dyn Fn() -> bool + Send + Sync>,
+    cancel: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl AbortOnDropHandle {
+    /// Constructs a handle from the primitives an [`Executor`] impl has on hand: a callback
+    /// reporting whether the task has finished, and a callback that cancels it.
+    pub fn new(
+        is_finished: impl Fn() -> bool + Send + Sync + 'static,
+        cancel: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        Self {
+            is_finished: Box::new(is_finished),
+            cancel: Some(Box::new(cancel)),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        (self.is_finished)()
+    }
+
+    /// Lets the task keep running after this handle is dropped.
+    pub fn detach(mut self) {
+        self.cancel = None;
+    }
+}
+
+impl Drop for AbortOnDropHandle {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel();
+        }
+    }
+}
+
+impl std::fmt::Debug for AbortOnDropHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AbortOnDropHandle").finish_non_exhaustive()
+    }
+}
+
+/// How many events [`ConnectionManager::start`]'s select loop services before it yields back to
+/// the runtime.
+const EVENTS_PER_TICK: usize = 16;
+
+/// Tracks how many events have been serviced in the current tick of an actor's select loop, so it
+/// can yield back to the runtime after `limit` of them rather than starving other tasks.
+struct TickBudget {
+    limit: usize,
+    processed: usize,
+}
+
+impl TickBudget {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            processed: 0,
+        }
+    }
+
+    /// Whether the budget for this tick has been used up and the caller should yield before
+    /// servicing another event.
+    fn should_yield(&self) -> bool {
+        self.processed >= self.limit
+    }
+
+    fn reset(&mut self) {
+        self.processed = 0;
+    }
+
+    fn record_event(&mut self) {
+        self.processed += 1;
+    }
 }
 
 pub struct ConnectionManager {
     endpoint: Arc<Endpoint>,
+    executor: Arc<dyn Executor>,
 
     mailbox: Fuse<tokio_stream::wrappers::ReceiverStream<ConnectionManagerRequest>>,
-    pending_connections: FuturesUnordered<JoinHandle<ConnectingOutput>>,
+    connecting_results: Fuse<tokio_stream::wrappers::UnboundedReceiverStream<ConnectingOutput>>,
+    connecting_result_sender: tokio::sync::mpsc::UnboundedSender<ConnectingOutput>,
+    // Keeps the in-flight connection-driver tasks alive, and aborts them if this manager is
+    // dropped. Pruned lazily whenever a new connection attempt is kicked off.
+    pending_connections: Vec<AbortOnDropHandle>,
 
     active_peers: ActivePeers,
     incoming: Fuse<Incoming>,
+    peer_events: Fuse<tokio_stream::wrappers::BroadcastStream<crate::types::PeerEvent>>,
 
     service: BoxCloneService<Request<Bytes>, Response<Bytes>, Infallible>,
+
+    // Addresses of peers we always want to stay connected to, along with our current backoff
+    // state for each.
+    persistent_peers: HashMap<SocketAddr, PersistentPeerState>,
+    // Reverse lookup from the id of a persistent peer we've connected to (kept after it
+    // disconnects) to its persistent-peer address, so that a `LostPeer` event can be turned back
+    // into a redial target and a later inbound reconnect from it can be recognized.
+    peer_addresses: HashMap<PeerId, SocketAddr>,
+    reconnection_policy: ReconnectionPolicy,
+    // Pending sleep futures that, once elapsed, trigger a redial of a persistent peer. Modeled
+    // as a `FuturesUnordered` rather than a `DelayQueue` so that it can sit empty between
+    // select! iterations without needing to be re-fused.
+    reconnect_queue: FuturesUnordered<BoxFuture<'static, SocketAddr>>,
 }
 
 impl ConnectionManager {
@@ -45,25 +234,73 @@ impl ConnectionManager {
         active_peers: ActivePeers,
         incoming: Incoming,
         service: BoxCloneService<Request<Bytes>, Response<Bytes>, Infallible>,
+        persistent_peers: Vec<SocketAddr>,
     ) -> (Self, tokio::sync::mpsc::Sender<ConnectionManagerRequest>) {
         let (sender, reciever) = tokio::sync::mpsc::channel(128);
+        let (connecting_result_sender, connecting_result_receiver) =
+            tokio::sync::mpsc::unbounded_channel();
+        let (peer_event_receiver, _currently_connected) = active_peers.subscribe();
+        let reconnection_policy = ReconnectionPolicy::default();
+        let persistent_peers = persistent_peers
+            .into_iter()
+            .map(|address| {
+                (
+                    address,
+                    PersistentPeerState::new(reconnection_policy.initial_delay),
+                )
+            })
+            .collect();
         (
             Self {
                 endpoint,
+                executor: Arc::new(TokioExecutor),
                 mailbox: tokio_stream::wrappers::ReceiverStream::new(reciever).fuse(),
-                pending_connections: FuturesUnordered::new(),
+                connecting_results: tokio_stream::wrappers::UnboundedReceiverStream::new(
+                    connecting_result_receiver,
+                )
+                .fuse(),
+                connecting_result_sender,
+                pending_connections: Vec::new(),
                 active_peers,
                 incoming: incoming.fuse(),
+                peer_events: tokio_stream::wrappers::BroadcastStream::new(peer_event_receiver)
+                    .fuse(),
                 service,
+                persistent_peers,
+                peer_addresses: HashMap::new(),
+                reconnection_policy,
+                reconnect_queue: FuturesUnordered::new(),
             },
             sender,
         )
     }
 
+    /// Overrides the [`Executor`] used to drive connection attempts and per-peer request
+    /// handlers. Defaults to spawning onto the ambient tokio runtime.
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
     pub async fn start(mut self) {
         info!("ConnectionManager started");
 
+        let initial_targets: Vec<SocketAddr> = self.persistent_peers.keys().copied().collect();
+        for address in initial_targets {
+            self.dial(address, None);
+        }
+
+        // Caps how many events we service before yielding back to the runtime, so that a fast
+        // stream of events on one branch (e.g. inbound connections) can't starve the others, and
+        // a long run of processing can't freeze the whole actor.
+        let mut budget = TickBudget::new(EVENTS_PER_TICK);
+
         loop {
+            if budget.should_yield() {
+                budget.reset();
+                tokio::task::yield_now().await;
+            }
+
             futures::select! {
                 request = self.mailbox.select_next_some() => {
                     info!("recieved new request");
@@ -76,11 +313,19 @@ impl ConnectionManager {
                 connecting = self.incoming.select_next_some() => {
                     self.handle_incoming(connecting);
                 },
-                connecting_output = self.pending_connections.select_next_some() => {
+                connecting_output = self.connecting_results.select_next_some() => {
                     self.handle_connecting_result(connecting_output);
                 },
+                address = self.reconnect_queue.select_next_some() => {
+                    self.handle_reconnect(address);
+                },
+                peer_event = self.peer_events.select_next_some() => {
+                    self.handle_peer_event(peer_event);
+                },
                 complete => break,
             }
+
+            budget.record_event();
         }
 
         info!("ConnectionManager ended");
@@ -97,7 +342,10 @@ impl ConnectionManager {
                 self.active_peers.clone(),
             );
 
-            tokio::spawn(request_handler.start());
+            // The handler should keep running independently of this manager's lifetime.
+            self.executor
+                .spawn(request_handler.start().boxed())
+                .detach();
         }
     }
 
@@ -105,30 +353,55 @@ impl ConnectionManager {
         &mut self,
         address: SocketAddr,
         oneshot: tokio::sync::oneshot::Sender<Result<PeerId>>,
+    ) {
+        self.dial(address, Some(oneshot));
+    }
+
+    /// Kicks off an outbound connection attempt to `address`, optionally reporting the result
+    /// back through `maybe_oneshot`.
+    fn dial(
+        &mut self,
+        address: SocketAddr,
+        maybe_oneshot: Option<tokio::sync::oneshot::Sender<Result<PeerId>>>,
     ) {
         let connecting = self.endpoint.connect(address);
-        let join_handle = JoinHandle(tokio::spawn(async move {
-            let connecting_result = match connecting {
-                Ok(connecting) => connecting.await,
-                Err(e) => Err(e),
-            };
-            ConnectingOutput {
-                connecting_result,
-                maybe_oneshot: Some(oneshot),
+        let result_sender = self.connecting_result_sender.clone();
+        let handle = self.executor.spawn(
+            async move {
+                let connecting_result = match connecting {
+                    Ok(connecting) => connecting.await,
+                    Err(e) => Err(e),
+                };
+                let _ = result_sender.send(ConnectingOutput {
+                    connecting_result,
+                    maybe_oneshot,
+                    target: Some(address),
+                });
             }
-        }));
-        self.pending_connections.push(join_handle);
+            .boxed(),
+        );
+        self.pending_connections
+            .retain(|handle| !handle.is_finished());
+        self.pending_connections.push(handle);
     }
 
     fn handle_incoming(&mut self, connecting: Connecting) {
         info!("recieved new incoming connection");
-        let join_handle = JoinHandle(tokio::spawn(connecting.map(|connecting_result| {
-            ConnectingOutput {
-                connecting_result,
-                maybe_oneshot: None,
-            }
-        })));
-        self.pending_connections.push(join_handle);
+        let result_sender = self.connecting_result_sender.clone();
+        let handle = self.executor.spawn(
+            connecting
+                .map(move |connecting_result| {
+                    let _ = result_sender.send(ConnectingOutput {
+                        connecting_result,
+                        maybe_oneshot: None,
+                        target: None,
+                    });
+                })
+                .boxed(),
+        );
+        self.pending_connections
+            .retain(|handle| !handle.is_finished());
+        self.pending_connections.push(handle);
     }
 
     fn handle_connecting_result(
@@ -136,12 +409,52 @@ impl ConnectionManager {
         ConnectingOutput {
             connecting_result,
             maybe_oneshot,
+            target,
         }: ConnectingOutput,
     ) {
         match connecting_result {
             Ok(new_connection) => {
                 info!("new connection complete");
                 let peer_id = new_connection.connection.peer_id();
+
+                if !self.active_peers.is_allowed(&peer_id) {
+                    info!(
+                        "closing connection with {peer_id:?}: {:?}",
+                        crate::types::DisconnectReason::NotAllowlisted
+                    );
+                    new_connection.connection.close();
+
+                    // The connection never made it into active_peers, so no LostPeer event will
+                    // fire for it; a persistent peer that's fallen off the allowlist still needs
+                    // to be redialed (it may be re-added to the allowlist before the retry fires).
+                    if let Some(address) =
+                        target.filter(|address| self.persistent_peers.contains_key(address))
+                    {
+                        self.schedule_reconnect(address);
+                    }
+                    if let Some(oneshot) = maybe_oneshot {
+                        let _ = oneshot.send(Err(std::io::Error::new(
+                            std::io::ErrorKind::PermissionDenied,
+                            "peer is not on the connect allowlist",
+                        )
+                        .into()));
+                    }
+                    return;
+                }
+
+                // Prefer the address we already track this peer under, if any: an inbound
+                // connection's remote address is the peer's ephemeral/NAT'd source port, which
+                // essentially never matches the address we'd configured and dialed, so relying on
+                // it alone would never recognize a persistent peer that reconnects to us inbound.
+                let address = self
+                    .peer_addresses
+                    .get(&peer_id)
+                    .copied()
+                    .or(target)
+                    .unwrap_or_else(|| new_connection.connection.remote_address());
+                if self.persistent_peers.contains_key(&address) {
+                    self.on_persistent_peer_connected(address, peer_id);
+                }
                 self.add_peer(new_connection);
                 if let Some(oneshot) = maybe_oneshot {
                     let _ = oneshot.send(Ok(peer_id));
@@ -149,48 +462,129 @@ impl ConnectionManager {
             }
             Err(e) => {
                 error!("inbound connection failed: {e}");
+                if let Some(address) =
+                    target.filter(|address| self.persistent_peers.contains_key(address))
+                {
+                    self.schedule_reconnect(address);
+                }
                 if let Some(oneshot) = maybe_oneshot {
                     let _ = oneshot.send(Err(e));
                 }
             }
         }
     }
-}
 
-// JoinHandle that aborts on drop
-#[derive(Debug)]
-#[must_use]
-pub struct JoinHandle<T>(tokio::task::JoinHandle<T>);
+    fn on_persistent_peer_connected(&mut self, address: SocketAddr, peer_id: PeerId) {
+        if let Some(state) = self.persistent_peers.get_mut(&address) {
+            state.connected = true;
+            state.next_delay = self.reconnection_policy.initial_delay;
+        }
+        self.peer_addresses.insert(peer_id, address);
+    }
 
-impl<T> Drop for JoinHandle<T> {
-    fn drop(&mut self) {
-        self.0.abort();
+    /// Schedules a redial of `address` after the current backoff delay, and advances the
+    /// backoff for the *next* failure.
+    fn schedule_reconnect(&mut self, address: SocketAddr) {
+        let Some(state) = self.persistent_peers.get_mut(&address) else {
+            return;
+        };
+        state.connected = false;
+
+        let delay = self.reconnection_policy.jittered(state.next_delay);
+        state.next_delay = self.reconnection_policy.backoff(state.next_delay);
+
+        info!("scheduling reconnect to persistent peer {address} in {delay:?}");
+        self.reconnect_queue.push(
+            async move {
+                tokio::time::sleep(delay).await;
+                address
+            }
+            .boxed(),
+        );
     }
-}
 
-impl<T> std::future::Future for JoinHandle<T> {
-    type Output = T;
+    fn handle_reconnect(&mut self, address: SocketAddr) {
+        // The peer may have reconnected (e.g. by dialing us) while this retry was pending, in
+        // which case there's nothing left to do.
+        if matches!(self.persistent_peers.get(&address), Some(state) if state.connected) {
+            return;
+        }
 
-    fn poll(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        // If the task panics just propagate it up
-        std::pin::Pin::new(&mut self.0).poll(cx).map(Result::unwrap)
+        self.dial(address, None);
+    }
+
+    fn handle_peer_event(
+        &mut self,
+        peer_event: std::result::Result<
+            crate::types::PeerEvent,
+            tokio_stream::wrappers::errors::BroadcastStreamRecvError,
+        >,
+    ) {
+        match peer_event {
+            Ok(crate::types::PeerEvent::LostPeer(peer_id, _reason)) => {
+                if let Some(address) = self.peer_addresses.get(&peer_id).copied() {
+                    if self.persistent_peers.contains_key(&address) {
+                        self.schedule_reconnect(address);
+                    }
+                }
+            }
+            Ok(crate::types::PeerEvent::NewPeer(_)) => {}
+            Err(e) => {
+                error!("peer event stream lagged: {e}");
+            }
+        }
     }
 }
 
+/// The relationship between us and a peer on our connect allowlist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAffinity {
+    /// Explicitly configured by an operator (e.g. a validator set). Eligible for automatic
+    /// reconnection and never evicted to make room for other peers.
+    Known,
+    /// Accepted opportunistically, e.g. via discovery. May be evicted under peer-set pressure.
+    Discovered,
+}
+
 #[derive(Debug, Clone)]
 pub struct ActivePeers(Arc<std::sync::RwLock<ActivePeersInner>>);
 
 impl ActivePeers {
-    pub fn new(channel_size: usize) -> Self {
+    pub fn new(channel_size: usize, peer_limits: PeerLimits) -> Self {
         Self(Arc::new(std::sync::RwLock::new(ActivePeersInner::new(
             channel_size,
+            peer_limits,
         ))))
     }
 
-    #[allow(unused)]
+    /// Adds `peer_id` to the connect allowlist with the given `affinity`, or updates its
+    /// affinity if it's already present.
+    pub fn allow_peer(&self, peer_id: PeerId, affinity: PeerAffinity) {
+        self.0.write().unwrap().allow_peer(peer_id, affinity)
+    }
+
+    /// Removes `peer_id` from the connect allowlist. Does not close an already-active
+    /// connection to that peer.
+    pub fn disallow_peer(&self, peer_id: &PeerId) {
+        self.0.write().unwrap().disallow_peer(peer_id)
+    }
+
+    /// Returns this peer's allowlist relation, if it's on the allowlist at all.
+    pub fn affinity(&self, peer_id: &PeerId) -> Option<PeerAffinity> {
+        self.0.read().unwrap().affinity(peer_id)
+    }
+
+    /// Returns whether `peer_id` is allowed to connect: always `true` unless allowlist
+    /// enforcement is enabled, in which case only peers on the allowlist are allowed.
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.0.read().unwrap().is_allowed(peer_id)
+    }
+
+    /// Enables or disables allowlist enforcement for incoming and outbound connections.
+    pub fn set_allowlist_enforced(&self, enforced: bool) {
+        self.0.write().unwrap().allowlist_enforced = enforced;
+    }
+
     pub fn subscribe(
         &self,
     ) -> (
@@ -230,22 +624,82 @@ impl ActivePeers {
     }
 }
 
+/// An active connection along with the bookkeeping needed to evict it under peer-set pressure.
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    connection: Connection,
+    connected_at: std::time::Instant,
+}
+
+/// Caps on the number of simultaneously active peers, tracked separately per direction so that
+/// a flood of inbound dials can't starve our ability to maintain outbound connections.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: usize::MAX,
+            max_outbound: usize::MAX,
+        }
+    }
+}
+
+/// Picks the least-valuable peer to evict from `candidates`, given each one's allowlist
+/// `PeerAffinity` (if any) and the time it connected. `Known` peers are filtered out entirely
+/// before ranking — they're never evicted to make room for others, so if every candidate is
+/// `Known` this returns `None` and the caller should reject the new connection instead.
+fn pick_eviction_candidate(
+    candidates: impl Iterator<Item = (PeerId, Option<PeerAffinity>, std::time::Instant)>,
+) -> Option<PeerId> {
+    candidates
+        .filter(|(_, affinity, _)| *affinity != Some(PeerAffinity::Known))
+        .min_by_key(|(_, _, connected_at)| *connected_at)
+        .map(|(peer_id, _, _)| peer_id)
+}
+
 #[derive(Debug)]
 pub struct ActivePeersInner {
-    connections: HashMap<PeerId, Connection>,
+    connections: HashMap<PeerId, PeerEntry>,
     peer_event_sender: tokio::sync::broadcast::Sender<crate::types::PeerEvent>,
+
+    allowed_peers: HashMap<PeerId, PeerAffinity>,
+    allowlist_enforced: bool,
+
+    peer_limits: PeerLimits,
 }
 
 impl ActivePeersInner {
-    fn new(channel_size: usize) -> Self {
+    fn new(channel_size: usize, peer_limits: PeerLimits) -> Self {
         let (sender, _reciever) = tokio::sync::broadcast::channel(channel_size);
         Self {
             connections: Default::default(),
             peer_event_sender: sender,
+            allowed_peers: Default::default(),
+            allowlist_enforced: false,
+            peer_limits,
         }
     }
 
-    #[allow(unused)]
+    fn allow_peer(&mut self, peer_id: PeerId, affinity: PeerAffinity) {
+        self.allowed_peers.insert(peer_id, affinity);
+    }
+
+    fn disallow_peer(&mut self, peer_id: &PeerId) {
+        self.allowed_peers.remove(peer_id);
+    }
+
+    fn affinity(&self, peer_id: &PeerId) -> Option<PeerAffinity> {
+        self.allowed_peers.get(peer_id).copied()
+    }
+
+    fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        !self.allowlist_enforced || self.allowed_peers.contains_key(peer_id)
+    }
+
     fn subscribe(
         &self,
     ) -> (
@@ -262,13 +716,15 @@ impl ActivePeersInner {
     }
 
     fn get(&self, peer_id: &PeerId) -> Option<Connection> {
-        self.connections.get(peer_id).cloned()
+        self.connections
+            .get(peer_id)
+            .map(|entry| entry.connection.clone())
     }
 
     fn remove(&mut self, peer_id: &PeerId, reason: crate::types::DisconnectReason) {
-        if let Some(connection) = self.connections.remove(peer_id) {
+        if let Some(entry) = self.connections.remove(peer_id) {
             // maybe actually provide reason to other side?
-            connection.close();
+            entry.connection.close();
 
             self.send_event(crate::types::PeerEvent::LostPeer(*peer_id, reason));
         }
@@ -283,10 +739,10 @@ impl ActivePeersInner {
         match self.connections.entry(peer_id) {
             Entry::Occupied(entry) => {
                 // Only remove the entry if the stable id matches
-                if entry.get().stable_id() == stable_id {
-                    let (peer_id, connection) = entry.remove_entry();
+                if entry.get().connection.stable_id() == stable_id {
+                    let (peer_id, entry) = entry.remove_entry();
                     // maybe actually provide reason to other side?
-                    connection.close();
+                    entry.connection.close();
 
                     self.send_event(crate::types::PeerEvent::LostPeer(peer_id, reason));
                 }
@@ -295,6 +751,46 @@ impl ActivePeersInner {
         }
     }
 
+    fn count_by_origin(&self, origin: ConnectionOrigin) -> usize {
+        self.connections
+            .values()
+            .filter(|entry| entry.connection.origin() == origin)
+            .count()
+    }
+
+    /// Picks the least-valuable currently-connected peer of the given `origin` to evict to make
+    /// room for a new connection. `Known` peers are never candidates, per
+    /// [`PeerAffinity::Known`]'s contract; among the rest, the one connected longest ago is
+    /// picked.
+    fn eviction_candidate(&self, origin: ConnectionOrigin) -> Option<PeerId> {
+        pick_eviction_candidate(self.connections.iter().filter_map(|(peer_id, entry)| {
+            (entry.connection.origin() == origin)
+                .then(|| (*peer_id, self.affinity(peer_id), entry.connected_at))
+        }))
+    }
+
+    /// Ensures there's a free `origin` slot for an incoming connection, evicting the
+    /// least-valuable existing peer of that origin if we're at capacity. Returns `false` if no
+    /// peer could be evicted and the new connection should be rejected instead.
+    fn make_room_for(&mut self, origin: ConnectionOrigin) -> bool {
+        let limit = match origin {
+            ConnectionOrigin::Inbound => self.peer_limits.max_inbound,
+            ConnectionOrigin::Outbound => self.peer_limits.max_outbound,
+        };
+
+        if self.count_by_origin(origin) < limit {
+            return true;
+        }
+
+        let Some(victim) = self.eviction_candidate(origin) else {
+            return false;
+        };
+
+        info!("evicting peer {victim:?} to make room for a new {origin:?} connection");
+        self.remove(&victim, crate::types::DisconnectReason::TooManyPeers);
+        true
+    }
+
     fn send_event(&self, event: crate::types::PeerEvent) {
         // We don't care if anyone is listening
         let _ = self.peer_event_sender.send(event);
@@ -309,31 +805,53 @@ impl ActivePeersInner {
         // TODO drop Connection if you've somehow connected out ourself
 
         let peer_id = new_connection.connection.peer_id();
-        match self.connections.entry(peer_id) {
-            Entry::Occupied(mut entry) => {
-                if Self::simultaneous_dial_tie_breaking(
-                    own_peer_id,
-                    &peer_id,
-                    entry.get().origin(),
-                    new_connection.connection.origin(),
-                ) {
-                    info!("closing old connection with {peer_id:?} to mitigate simultaneous dial");
-                    let old_connection = entry.insert(new_connection.connection.clone());
-                    old_connection.close();
-                    self.send_event(crate::types::PeerEvent::LostPeer(
-                        peer_id,
-                        crate::types::DisconnectReason::Requested,
-                    ));
-                } else {
-                    info!("closing new connection with {peer_id:?} to mitigate simultaneous dial");
-                    new_connection.connection.close();
-                    // Early return to avoid standing up Incoming Request handlers
-                    return None;
+
+        if let Some(existing) = self.connections.get(&peer_id) {
+            if Self::simultaneous_dial_tie_breaking(
+                own_peer_id,
+                &peer_id,
+                existing.connection.origin(),
+                new_connection.connection.origin(),
+            ) {
+                info!("closing old connection with {peer_id:?} to mitigate simultaneous dial");
+                let old_entry = self.connections.insert(
+                    peer_id,
+                    PeerEntry {
+                        connection: new_connection.connection.clone(),
+                        connected_at: std::time::Instant::now(),
+                    },
+                );
+                if let Some(old_entry) = old_entry {
+                    old_entry.connection.close();
                 }
+                self.send_event(crate::types::PeerEvent::LostPeer(
+                    peer_id,
+                    crate::types::DisconnectReason::Requested,
+                ));
+            } else {
+                info!("closing new connection with {peer_id:?} to mitigate simultaneous dial");
+                new_connection.connection.close();
+                // Early return to avoid standing up Incoming Request handlers
+                return None;
             }
-            Entry::Vacant(entry) => {
-                entry.insert(new_connection.connection.clone());
+        } else {
+            let origin = new_connection.connection.origin();
+            if !self.make_room_for(origin) {
+                info!(
+                    "rejecting new connection with {peer_id:?}: {:?}",
+                    crate::types::DisconnectReason::TooManyPeers
+                );
+                new_connection.connection.close();
+                return None;
             }
+
+            self.connections.insert(
+                peer_id,
+                PeerEntry {
+                    connection: new_connection.connection.clone(),
+                    connected_at: std::time::Instant::now(),
+                },
+            );
         }
 
         self.send_event(crate::types::PeerEvent::NewPeer(peer_id));
@@ -365,3 +883,180 @@ impl ActivePeersInner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// An [`Executor`] that doesn't run anything on its own: spawned futures just pile up until
+    /// the test drives them with [`run_all`](Self::run_all). Lets tests exercise
+    /// executor-dependent code (and [`AbortOnDropHandle`] cancellation/completion) without a
+    /// tokio runtime driving tasks in the background.
+    #[derive(Debug, Default)]
+    struct DeterministicExecutor {
+        tasks: Mutex<Vec<BoxFuture<'static, ()>>>,
+    }
+
+    impl DeterministicExecutor {
+        async fn run_all(&self) {
+            let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+            for task in tasks {
+                task.await;
+            }
+        }
+    }
+
+    impl Executor for DeterministicExecutor {
+        fn spawn(&self, fut: BoxFuture<'static, ()>) -> AbortOnDropHandle {
+            let finished = Arc::new(AtomicBool::new(false));
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let finished_in_task = finished.clone();
+            let cancelled_in_task = cancelled.clone();
+            self.tasks.lock().unwrap().push(
+                async move {
+                    if !cancelled_in_task.load(Ordering::SeqCst) {
+                        fut.await;
+                    }
+                    finished_in_task.store(true, Ordering::SeqCst);
+                }
+                .boxed(),
+            );
+
+            AbortOnDropHandle::new(
+                move || finished.load(Ordering::SeqCst),
+                move || cancelled.store(true, Ordering::SeqCst),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_on_drop_handle_reports_finished_once_task_runs() {
+        let executor = DeterministicExecutor::default();
+        let handle = executor.spawn(async {}.boxed());
+
+        assert!(!handle.is_finished());
+        executor.run_all().await;
+        assert!(handle.is_finished());
+
+        handle.detach();
+    }
+
+    #[tokio::test]
+    async fn abort_on_drop_handle_cancels_on_drop() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_in_task = ran.clone();
+        let handle = AbortOnDropHandle::new(
+            || false,
+            move || {
+                ran_in_task.store(true, Ordering::SeqCst);
+            },
+        );
+
+        drop(handle);
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reconnection_policy_backs_off_up_to_the_max_delay() {
+        let policy = ReconnectionPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            factor: 2.0,
+            jitter: 0.0,
+        };
+
+        let mut delay = policy.initial_delay;
+        for _ in 0..10 {
+            delay = policy.backoff(delay);
+            assert!(delay <= policy.max_delay);
+        }
+        assert_eq!(delay, policy.max_delay);
+    }
+
+    #[test]
+    fn reconnection_policy_jitter_stays_within_bounds() {
+        let policy = ReconnectionPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(60),
+            factor: 2.0,
+            jitter: 0.5,
+        };
+
+        for _ in 0..100 {
+            let jittered = policy.jittered(policy.initial_delay);
+            assert!(jittered >= policy.initial_delay.mul_f64(0.5));
+            assert!(jittered <= policy.initial_delay.mul_f64(1.5));
+        }
+    }
+
+    fn test_peer_id(byte: u8) -> PeerId {
+        PeerId([byte; 32])
+    }
+
+    #[test]
+    fn known_peers_are_never_picked_for_eviction() {
+        let now = std::time::Instant::now();
+        let known = test_peer_id(1);
+        let discovered = test_peer_id(2);
+
+        let victim = pick_eviction_candidate(
+            vec![
+                (known, Some(PeerAffinity::Known), now),
+                (discovered, Some(PeerAffinity::Discovered), now),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(victim, Some(discovered));
+    }
+
+    #[test]
+    fn no_candidate_is_picked_when_every_peer_is_known() {
+        let now = std::time::Instant::now();
+
+        let victim = pick_eviction_candidate(
+            vec![
+                (test_peer_id(1), Some(PeerAffinity::Known), now),
+                (test_peer_id(2), Some(PeerAffinity::Known), now),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(victim, None);
+    }
+
+    #[test]
+    fn eviction_breaks_ties_by_oldest_connection() {
+        let earlier = std::time::Instant::now();
+        let later = std::time::Instant::now();
+        let older = test_peer_id(1);
+        let newer = test_peer_id(2);
+
+        let victim = pick_eviction_candidate(
+            vec![
+                (older, Some(PeerAffinity::Discovered), earlier),
+                (newer, None, later),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(victim, Some(older));
+    }
+
+    #[test]
+    fn tick_budget_yields_once_the_limit_is_reached() {
+        let mut budget = TickBudget::new(2);
+        assert!(!budget.should_yield());
+
+        budget.record_event();
+        assert!(!budget.should_yield());
+
+        budget.record_event();
+        assert!(budget.should_yield());
+
+        budget.reset();
+        assert!(!budget.should_yield());
+    }
+}
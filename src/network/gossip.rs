@@ -0,0 +1,548 @@
+//! An epidemic (gossip) broadcast subsystem layered on top of [`ActivePeers`].
+//!
+//! Messages are disseminated using a Plumtree-style mix of eager push (full payload, along a
+//! small spanning-tree fanout) and lazy push (just the message id, to everyone else). Duplicate
+//! eager deliveries prune the tree; a lazy announcement for a message we never receive grafts the
+//! announcer back into the tree. This keeps per-message bandwidth close to a spanning tree while
+//! healing itself as peers come and go.
+
+use super::connection_manager::{ActivePeers, Executor, TokioExecutor};
+use crate::{types::PeerEvent, PeerId, Request};
+use bytes::Bytes;
+use futures::{
+    future::BoxFuture,
+    stream::{Fuse, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, trace};
+
+/// A content hash used to deduplicate gossiped messages. Not cryptographically strong; it only
+/// needs to be consistent across peers for the same payload.
+type MessageId = u64;
+
+fn message_id(payload: &[u8]) -> MessageId {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum WireMessage {
+    /// The full payload of a message, identified by `id`.
+    Gossip { id: MessageId, payload: Vec<u8> },
+    /// An announcement that the sender has seen `id`, without the payload.
+    IHave { id: MessageId },
+    /// Asks the receiver to stop eager-pushing to the sender; they're already covered via lazy
+    /// push.
+    Prune,
+    /// Asks the receiver to eager-push `id`'s payload to the sender and promote them back into
+    /// the eager-push set.
+    Graft { id: MessageId },
+}
+
+/// Tuning knobs for the gossip subsystem.
+#[derive(Debug, Clone, Copy)]
+pub struct GossipConfig {
+    /// The target number of peers to keep in the eager-push set; the rest are lazy-push.
+    pub eager_fanout: usize,
+    /// How long to remember a message id for deduplication purposes.
+    pub seen_cache_ttl: Duration,
+    /// The maximum number of message ids to remember at once.
+    pub seen_cache_capacity: usize,
+    /// How long to wait for a lazily-announced message to arrive before sending a GRAFT.
+    pub graft_timeout: Duration,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            eager_fanout: 4,
+            seen_cache_ttl: Duration::from_secs(60),
+            seen_cache_capacity: 4096,
+            graft_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A bounded, expiring cache of messages we've already delivered, keyed by [`MessageId`], so we
+/// can both suppress duplicates and re-serve payloads to peers that GRAFT.
+struct SeenCache {
+    entries: HashMap<MessageId, (Bytes, Instant)>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl SeenCache {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    fn evict_expired(&mut self) {
+        let ttl = self.ttl;
+        let now = Instant::now();
+        self.entries
+            .retain(|_, (_, inserted_at)| now.duration_since(*inserted_at) < ttl);
+    }
+
+    fn contains(&mut self, id: MessageId) -> bool {
+        self.evict_expired();
+        self.entries.contains_key(&id)
+    }
+
+    fn get(&self, id: MessageId) -> Option<Bytes> {
+        self.entries.get(&id).map(|(payload, _)| payload.clone())
+    }
+
+    fn insert(&mut self, id: MessageId, payload: Bytes) {
+        self.evict_expired();
+        if self.entries.len() >= self.capacity {
+            if let Some(&oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, inserted_at))| *inserted_at)
+                .map(|(id, _)| id)
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(id, (payload, Instant::now()));
+    }
+}
+
+enum GossipActorRequest {
+    Broadcast(Bytes),
+    Inbound { from: PeerId, message: WireMessage },
+}
+
+/// A cheap, cloneable handle to a running [`GossipService`].
+#[derive(Debug, Clone)]
+pub struct Gossip {
+    request_sender: tokio::sync::mpsc::Sender<GossipActorRequest>,
+    delivered_sender: tokio::sync::broadcast::Sender<Bytes>,
+}
+
+impl Gossip {
+    /// Creates a new gossip handle and its backing [`GossipService`]. The caller is responsible
+    /// for running the service, e.g. via `tokio::spawn(service.start())`.
+    pub fn new(active_peers: ActivePeers, config: GossipConfig) -> (Self, GossipService) {
+        let (request_sender, request_receiver) = tokio::sync::mpsc::channel(128);
+        let (delivered_sender, _delivered_receiver) = tokio::sync::broadcast::channel(128);
+
+        let handle = Self {
+            request_sender,
+            delivered_sender: delivered_sender.clone(),
+        };
+        let service = GossipService::new(active_peers, config, request_receiver, delivered_sender);
+
+        (handle, service)
+    }
+
+    /// Disseminates `payload` to all reachable peers.
+    pub async fn broadcast(&self, payload: Bytes) {
+        let _ = self
+            .request_sender
+            .send(GossipActorRequest::Broadcast(payload))
+            .await;
+    }
+
+    /// Subscribes to messages delivered to us, either originated locally or received from a
+    /// peer.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Bytes> {
+        self.delivered_sender.subscribe()
+    }
+
+    /// Feeds an inbound wire message from `from` into the gossip actor. Intended to be called by
+    /// whatever demultiplexes incoming peer requests onto their destination subsystem.
+    pub(crate) async fn receive(&self, from: PeerId, message_bytes: &[u8]) {
+        if let Ok(message) = bincode::deserialize::<WireMessage>(message_bytes) {
+            let _ = self
+                .request_sender
+                .send(GossipActorRequest::Inbound { from, message })
+                .await;
+        }
+    }
+}
+
+/// The actor driving the Plumtree state machine: tree membership, message dedup, and
+/// GRAFT/PRUNE repair.
+pub struct GossipService {
+    executor: Arc<dyn Executor>,
+    active_peers: ActivePeers,
+    config: GossipConfig,
+
+    mailbox: Fuse<tokio_stream::wrappers::ReceiverStream<GossipActorRequest>>,
+    peer_events: Fuse<tokio_stream::wrappers::BroadcastStream<PeerEvent>>,
+    delivered_sender: tokio::sync::broadcast::Sender<Bytes>,
+
+    eager_push: HashSet<PeerId>,
+    lazy_push: HashSet<PeerId>,
+    seen: SeenCache,
+
+    // The first peer to announce a not-yet-received message, so we know who to GRAFT if it
+    // doesn't show up in time.
+    pending_grafts: HashMap<MessageId, PeerId>,
+    graft_timers: FuturesUnordered<BoxFuture<'static, MessageId>>,
+}
+
+impl GossipService {
+    fn new(
+        active_peers: ActivePeers,
+        config: GossipConfig,
+        request_receiver: tokio::sync::mpsc::Receiver<GossipActorRequest>,
+        delivered_sender: tokio::sync::broadcast::Sender<Bytes>,
+    ) -> Self {
+        let (peer_event_receiver, currently_connected) = active_peers.subscribe();
+
+        let mut eager_push = HashSet::new();
+        let mut lazy_push = HashSet::new();
+        for peer_id in currently_connected {
+            if eager_push.len() < config.eager_fanout {
+                eager_push.insert(peer_id);
+            } else {
+                lazy_push.insert(peer_id);
+            }
+        }
+
+        Self {
+            executor: Arc::new(TokioExecutor),
+            active_peers,
+            seen: SeenCache::new(config.seen_cache_ttl, config.seen_cache_capacity),
+            config,
+            mailbox: tokio_stream::wrappers::ReceiverStream::new(request_receiver).fuse(),
+            peer_events: tokio_stream::wrappers::BroadcastStream::new(peer_event_receiver).fuse(),
+            delivered_sender,
+            eager_push,
+            lazy_push,
+            pending_grafts: HashMap::new(),
+            graft_timers: FuturesUnordered::new(),
+        }
+    }
+
+    /// Overrides the [`Executor`] used to drive outbound sends. Defaults to the ambient tokio
+    /// runtime.
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    pub async fn start(mut self) {
+        info!("Gossip service started");
+
+        loop {
+            futures::select! {
+                request = self.mailbox.select_next_some() => {
+                    match request {
+                        GossipActorRequest::Broadcast(payload) => self.originate(payload),
+                        GossipActorRequest::Inbound { from, message } => {
+                            self.handle_message(from, message);
+                        }
+                    }
+                },
+                peer_event = self.peer_events.select_next_some() => {
+                    self.handle_peer_event(peer_event);
+                },
+                id = self.graft_timers.select_next_some() => {
+                    self.handle_graft_timeout(id);
+                },
+                complete => break,
+            }
+        }
+
+        info!("Gossip service ended");
+    }
+
+    fn originate(&mut self, payload: Bytes) {
+        let id = message_id(&payload);
+        if self.seen.contains(id) {
+            return;
+        }
+        self.seen.insert(id, payload.clone());
+        let _ = self.delivered_sender.send(payload.clone());
+
+        self.push_full(id, &payload, None);
+        self.push_ihave(id, None);
+    }
+
+    fn handle_message(&mut self, from: PeerId, message: WireMessage) {
+        match message {
+            WireMessage::Gossip { id, payload } => self.handle_gossip(from, id, payload.into()),
+            WireMessage::IHave { id } => self.handle_ihave(from, id),
+            WireMessage::Prune => self.demote_to_lazy(from),
+            WireMessage::Graft { id } => self.handle_graft(from, id),
+        }
+    }
+
+    fn handle_gossip(&mut self, from: PeerId, id: MessageId, payload: Bytes) {
+        if self.seen.contains(id) {
+            trace!("already seen message {id}, pruning {from:?}");
+            self.send(from, WireMessage::Prune);
+            self.demote_to_lazy(from);
+            return;
+        }
+
+        self.seen.insert(id, payload.clone());
+        self.pending_grafts.remove(&id);
+        let _ = self.delivered_sender.send(payload.clone());
+
+        self.push_full(id, &payload, Some(from));
+        self.push_ihave(id, Some(from));
+    }
+
+    fn handle_ihave(&mut self, from: PeerId, id: MessageId) {
+        if self.seen.contains(id) || self.pending_grafts.contains_key(&id) {
+            return;
+        }
+
+        self.pending_grafts.insert(id, from);
+        let timeout = self.config.graft_timeout;
+        self.graft_timers.push(
+            async move {
+                tokio::time::sleep(timeout).await;
+                id
+            }
+            .boxed(),
+        );
+    }
+
+    fn handle_graft_timeout(&mut self, id: MessageId) {
+        // The message may have arrived (or been grafted already) while the timer was pending.
+        let Some(from) = self.pending_grafts.remove(&id) else {
+            return;
+        };
+        if self.seen.contains(id) {
+            return;
+        }
+
+        info!("grafting {from:?} back into the eager-push set for message {id}");
+        self.send(from, WireMessage::Graft { id });
+        self.promote_to_eager(from);
+    }
+
+    fn handle_graft(&mut self, from: PeerId, id: MessageId) {
+        self.promote_to_eager(from);
+        if let Some(payload) = self.seen.get(id) {
+            self.send(
+                from,
+                WireMessage::Gossip {
+                    id,
+                    payload: payload.to_vec(),
+                },
+            );
+        }
+    }
+
+    fn handle_peer_event(
+        &mut self,
+        peer_event: std::result::Result<
+            PeerEvent,
+            tokio_stream::wrappers::errors::BroadcastStreamRecvError,
+        >,
+    ) {
+        match peer_event {
+            Ok(PeerEvent::NewPeer(peer_id)) => {
+                if self.eager_push.len() < self.config.eager_fanout {
+                    self.eager_push.insert(peer_id);
+                } else {
+                    self.lazy_push.insert(peer_id);
+                }
+            }
+            Ok(PeerEvent::LostPeer(peer_id, _reason)) => {
+                self.eager_push.remove(&peer_id);
+                self.lazy_push.remove(&peer_id);
+                self.rebalance();
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Promotes a lazy-push peer to eager-push if we've dropped below our target fanout, e.g.
+    /// after a peer disconnects.
+    fn rebalance(&mut self) {
+        while self.eager_push.len() < self.config.eager_fanout {
+            let Some(&candidate) = self.lazy_push.iter().next() else {
+                break;
+            };
+            self.lazy_push.remove(&candidate);
+            self.eager_push.insert(candidate);
+        }
+    }
+
+    fn promote_to_eager(&mut self, peer_id: PeerId) {
+        self.lazy_push.remove(&peer_id);
+        self.eager_push.insert(peer_id);
+    }
+
+    fn demote_to_lazy(&mut self, peer_id: PeerId) {
+        if self.eager_push.remove(&peer_id) {
+            self.lazy_push.insert(peer_id);
+        }
+    }
+
+    fn push_full(&mut self, id: MessageId, payload: &Bytes, except: Option<PeerId>) {
+        let targets: Vec<PeerId> = self
+            .eager_push
+            .iter()
+            .copied()
+            .filter(|peer_id| Some(*peer_id) != except)
+            .collect();
+        for peer_id in targets {
+            self.send(
+                peer_id,
+                WireMessage::Gossip {
+                    id,
+                    payload: payload.to_vec(),
+                },
+            );
+        }
+    }
+
+    fn push_ihave(&mut self, id: MessageId, except: Option<PeerId>) {
+        let targets: Vec<PeerId> = self
+            .lazy_push
+            .iter()
+            .copied()
+            .filter(|peer_id| Some(*peer_id) != except)
+            .collect();
+        for peer_id in targets {
+            self.send(peer_id, WireMessage::IHave { id });
+        }
+    }
+
+    fn send(&self, peer_id: PeerId, message: WireMessage) {
+        let Some(connection) = self.active_peers.get(&peer_id) else {
+            return;
+        };
+        let Ok(bytes) = bincode::serialize(&message) else {
+            return;
+        };
+
+        self.executor
+            .spawn(
+                async move {
+                    let _ = connection.rpc(Request::new(Bytes::from(bytes))).await;
+                }
+                .boxed(),
+            )
+            .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::connection_manager::PeerLimits;
+
+    fn test_peer_id(byte: u8) -> PeerId {
+        PeerId([byte; 32])
+    }
+
+    fn new_service(config: GossipConfig) -> (Gossip, GossipService) {
+        let active_peers = ActivePeers::new(128, PeerLimits::default());
+        Gossip::new(active_peers, config)
+    }
+
+    #[test]
+    fn originate_delivers_to_our_own_subscriber() {
+        let (gossip, mut service) = new_service(GossipConfig::default());
+        let mut subscriber = gossip.subscribe();
+        let payload = Bytes::from_static(b"hello");
+
+        service.originate(payload.clone());
+
+        assert_eq!(subscriber.try_recv().unwrap(), payload);
+    }
+
+    #[test]
+    fn originate_is_a_noop_for_an_already_seen_message() {
+        let (gossip, mut service) = new_service(GossipConfig::default());
+        let mut subscriber = gossip.subscribe();
+        let payload = Bytes::from_static(b"hello");
+
+        service.originate(payload.clone());
+        subscriber.try_recv().unwrap();
+        service.originate(payload);
+
+        assert!(matches!(
+            subscriber.try_recv(),
+            Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    #[test]
+    fn duplicate_gossip_prunes_the_sender_out_of_the_eager_set() {
+        let (_gossip, mut service) = new_service(GossipConfig::default());
+        let peer = test_peer_id(1);
+        service.eager_push.insert(peer);
+
+        let payload = Bytes::from_static(b"hello");
+        let id = message_id(&payload);
+        service.seen.insert(id, payload.clone());
+
+        service.handle_gossip(peer, id, payload);
+
+        assert!(!service.eager_push.contains(&peer));
+        assert!(service.lazy_push.contains(&peer));
+    }
+
+    #[test]
+    fn ihave_for_an_unseen_message_grafts_the_announcer_after_the_timeout() {
+        let (_gossip, mut service) = new_service(GossipConfig::default());
+        let peer = test_peer_id(2);
+        let id = message_id(b"world");
+
+        service.handle_ihave(peer, id);
+        assert_eq!(service.pending_grafts.get(&id), Some(&peer));
+
+        service.handle_graft_timeout(id);
+
+        assert!(service.pending_grafts.is_empty());
+        assert!(service.eager_push.contains(&peer));
+        assert!(!service.lazy_push.contains(&peer));
+    }
+
+    #[test]
+    fn graft_timeout_is_a_noop_once_the_message_has_arrived() {
+        let (_gossip, mut service) = new_service(GossipConfig::default());
+        let peer = test_peer_id(3);
+        let payload = Bytes::from_static(b"already here");
+        let id = message_id(&payload);
+
+        service.handle_ihave(peer, id);
+        service.seen.insert(id, payload);
+
+        service.handle_graft_timeout(id);
+
+        assert!(!service.eager_push.contains(&peer));
+    }
+
+    #[test]
+    fn losing_a_peer_rebalances_a_lazy_peer_into_the_eager_set() {
+        let (_gossip, mut service) = new_service(GossipConfig {
+            eager_fanout: 1,
+            ..Default::default()
+        });
+        let eager_peer = test_peer_id(4);
+        let lazy_peer = test_peer_id(5);
+        service.eager_push.insert(eager_peer);
+        service.lazy_push.insert(lazy_peer);
+
+        service.handle_peer_event(Ok(PeerEvent::LostPeer(
+            eager_peer,
+            crate::types::DisconnectReason::Requested,
+        )));
+
+        assert!(service.eager_push.contains(&lazy_peer));
+        assert!(service.lazy_push.is_empty());
+    }
+}